@@ -0,0 +1,123 @@
+//! The client side of an SMTP connection: the TLS parameters used to secure it, and the
+//! [`net::NetworkStream`] that carries it.
+
+pub mod net;
+
+use std::sync::Arc;
+
+#[cfg(feature = "native-tls")]
+use native_tls::TlsConnector;
+#[cfg(feature = "rustls-tls")]
+use rustls::ClientConfig;
+
+pub use net::NetworkStream;
+
+use crate::transport::smtp::{error, Error};
+
+/// A callback enforcing certificate/SPKI pinning or another custom trust policy. Invoked with
+/// the peer's DER-encoded certificate chain, leaf first, once the TLS handshake completes; the
+/// connection is rejected if it returns `false`. `Arc`-wrapped so it can be cloned into a
+/// resumable [`net::MidHandshake`].
+pub(crate) type PeerCertificateVerifier = Arc<dyn Fn(&[Vec<u8>]) -> bool + Send + Sync>;
+
+/// How the TLS connection is actually established once [`TlsParameters`] has been built.
+#[derive(Clone)]
+pub(crate) enum InnerTlsParameters {
+    #[cfg(feature = "native-tls")]
+    NativeTls(TlsConnector),
+    #[cfg(feature = "rustls-tls")]
+    RustlsTls(Arc<ClientConfig>),
+}
+
+/// Parameters used to secure a connection to an SMTP server with TLS.
+///
+/// Build one with [`TlsParameters::builder`].
+#[derive(Clone)]
+pub struct TlsParameters {
+    pub(crate) connector: InnerTlsParameters,
+    domain: String,
+    peer_verifier: Option<PeerCertificateVerifier>,
+}
+
+impl TlsParameters {
+    /// Starts building `TlsParameters` for `domain`, the name the server's certificate is
+    /// expected to be valid for.
+    pub fn builder(domain: String) -> TlsParametersBuilder {
+        TlsParametersBuilder::new(domain)
+    }
+
+    pub(crate) fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Returns the configured certificate-pinning callback, if any.
+    pub(crate) fn peer_verifier(&self) -> Option<PeerCertificateVerifier> {
+        self.peer_verifier.clone()
+    }
+}
+
+/// Builds [`TlsParameters`].
+pub struct TlsParametersBuilder {
+    domain: String,
+    peer_verifier: Option<PeerCertificateVerifier>,
+}
+
+impl TlsParametersBuilder {
+    fn new(domain: String) -> Self {
+        Self {
+            domain,
+            peer_verifier: None,
+        }
+    }
+
+    /// Registers a callback to run against the peer's DER-encoded certificate chain (leaf
+    /// first) once the handshake completes, enforcing certificate/SPKI pinning or another
+    /// custom trust policy on top of whatever verification the TLS backend itself performs.
+    /// The connection is rejected if the callback returns `false`.
+    ///
+    /// Note: with the `native-tls` backend the chain only ever contains the leaf certificate;
+    /// only `rustls-tls` exposes the full chain the server sent.
+    pub fn peer_verifier<F>(mut self, verifier: F) -> Self
+    where
+        F: Fn(&[Vec<u8>]) -> bool + Send + Sync + 'static,
+    {
+        self.peer_verifier = Some(Arc::new(verifier));
+        self
+    }
+
+    /// Builds `TlsParameters` using the rustls backend.
+    #[cfg(feature = "rustls-tls")]
+    pub fn build_rustls(self) -> Result<TlsParameters, Error> {
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        Ok(TlsParameters {
+            connector: InnerTlsParameters::RustlsTls(Arc::new(config)),
+            domain: self.domain,
+            peer_verifier: self.peer_verifier,
+        })
+    }
+
+    /// Builds `TlsParameters` using the native-tls backend.
+    #[cfg(feature = "native-tls")]
+    pub fn build_native(self) -> Result<TlsParameters, Error> {
+        let connector = TlsConnector::new().map_err(error::tls)?;
+
+        Ok(TlsParameters {
+            connector: InnerTlsParameters::NativeTls(connector),
+            domain: self.domain,
+            peer_verifier: self.peer_verifier,
+        })
+    }
+}
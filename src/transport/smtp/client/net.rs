@@ -2,17 +2,25 @@ use std::{
     io::{self, Read, Write},
     mem,
     net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+use base64::{engine::general_purpose::STANDARD as base64_engine, Engine as _};
 #[cfg(feature = "native-tls")]
 use native_tls::TlsStream;
 #[cfg(feature = "rustls-tls")]
 use rustls::{ClientConnection, ServerName, StreamOwned};
-use socket2::{Domain, Protocol, Type};
+use socket2::{Domain, Protocol, TcpKeepalive, Type};
 
 #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
 use super::InnerTlsParameters;
+#[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+use super::PeerCertificateVerifier;
 use super::TlsParameters;
 use crate::transport::smtp::{error, Error};
 
@@ -34,10 +42,37 @@ enum InnerNetworkStream {
     /// Encrypted TCP stream
     #[cfg(feature = "rustls-tls")]
     RustlsTls(StreamOwned<ClientConnection, TcpStream>),
+    /// A user-provided transport, see [`NetworkStream::from_stream`]
+    Boxed(Box<dyn ReadWriteAbstraction>),
     /// Can't be built
     None,
 }
 
+/// Implemented by user-provided transports passed to [`NetworkStream::from_stream`].
+///
+/// Implement this for any `Read + Write` type — a Unix-domain socket, an in-memory pipe used in
+/// integration tests, or a pre-established tunnel — to let lettre drive it the same way it
+/// drives its own TCP and TLS streams.
+pub trait ReadWriteAbstraction: Read + Write + Send {
+    /// Returns the remote peer's address, if the transport has one.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Shuts down the read, write, or both halves of the transport.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Sets the timeout for read operations.
+    fn set_read_timeout(&mut self, duration: Option<Duration>) -> io::Result<()>;
+
+    /// Sets the timeout for write operations.
+    fn set_write_timeout(&mut self, duration: Option<Duration>) -> io::Result<()>;
+
+    /// Whether this transport is already encrypted, e.g. a TLS stream wrapped before being
+    /// handed to lettre.
+    fn is_encrypted(&self) -> bool {
+        false
+    }
+}
+
 impl NetworkStream {
     fn new(inner: InnerNetworkStream) -> Self {
         if let InnerNetworkStream::None = inner {
@@ -55,6 +90,7 @@ impl NetworkStream {
             InnerNetworkStream::NativeTls(ref s) => s.get_ref().peer_addr(),
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(ref s) => s.get_ref().peer_addr(),
+            InnerNetworkStream::Boxed(ref s) => s.peer_addr(),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(SocketAddr::V4(SocketAddrV4::new(
@@ -73,6 +109,7 @@ impl NetworkStream {
             InnerNetworkStream::NativeTls(ref s) => s.get_ref().shutdown(how),
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(ref s) => s.get_ref().shutdown(how),
+            InnerNetworkStream::Boxed(ref s) => s.shutdown(how),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(())
@@ -80,53 +117,58 @@ impl NetworkStream {
         }
     }
 
+    /// Wraps an arbitrary, already-established `Read + Write` transport so it can be driven
+    /// like a TCP or TLS `NetworkStream` — useful for Unix-domain sockets, in-memory pipes in
+    /// integration tests, or tunnels set up outside of [`NetworkStream::connect`].
+    ///
+    /// [`NetworkStream::upgrade_tls`] leaves such a stream as it is: it is never upgraded to TLS.
+    pub fn from_stream(stream: impl ReadWriteAbstraction + 'static) -> NetworkStream {
+        NetworkStream::new(InnerNetworkStream::Boxed(Box::new(stream)))
+    }
+
+    /// Connects to `server`, optionally upgrading to TLS. Resolves addresses one at a time and
+    /// connects to the first that succeeds; no proxying, address racing or socket tuning.
+    ///
+    /// This is `NetworkStream`'s original entry point, kept so existing callers built against
+    /// it don't break; new code wanting a proxy, RFC 8305 address racing or socket tuning should
+    /// use [`NetworkStream::connect_with_options`] instead.
     pub fn connect<T: ToSocketAddrs>(
         server: T,
         timeout: Option<Duration>,
         tls_parameters: Option<&TlsParameters>,
         local_addr: Option<IpAddr>,
     ) -> Result<NetworkStream, Error> {
-        fn try_connect<T: ToSocketAddrs>(
-            server: T,
-            timeout: Option<Duration>,
-            local_addr: Option<IpAddr>,
-        ) -> Result<TcpStream, Error> {
-            let addrs = server
-                .to_socket_addrs()
-                .map_err(error::connection)?
-                .filter(|resolved_addr| resolved_address_filter(resolved_addr, local_addr));
-
-            let mut last_err = None;
-
-            for addr in addrs {
-                let socket = socket2::Socket::new(
-                    Domain::for_address(addr),
-                    Type::STREAM,
-                    Some(Protocol::TCP),
-                )
-                .map_err(error::connection)?;
-                bind_local_address(&socket, &addr, local_addr)?;
-
-                if let Some(timeout) = timeout {
-                    match socket.connect_timeout(&addr.into(), timeout) {
-                        Ok(_) => return Ok(socket.into()),
-                        Err(err) => last_err = Some(err),
-                    }
-                } else {
-                    match socket.connect(&addr.into()) {
-                        Ok(_) => return Ok(socket.into()),
-                        Err(err) => last_err = Some(err),
-                    }
-                }
-            }
-
-            Err(match last_err {
-                Some(last_err) => error::connection(last_err),
-                None => error::connection("could not resolve to any address"),
-            })
+        let tcp_stream = connect_first_reachable(server, timeout, local_addr)?;
+        let mut stream = NetworkStream::new(InnerNetworkStream::Tcp(tcp_stream));
+        if let Some(tls_parameters) = tls_parameters {
+            stream.upgrade_tls(tls_parameters)?;
         }
+        Ok(stream)
+    }
 
-        let tcp_stream = try_connect(server, timeout, local_addr)?;
+    /// Connects to `server`, like [`NetworkStream::connect`], but with the proxying, RFC 8305
+    /// address-racing and socket-tuning options added since: tunnels through `proxy` if given,
+    /// races resolved addresses (staggered by `connection_attempt_delay`), and applies
+    /// `socket_options` to the TCP socket before connecting.
+    pub fn connect_with_options(
+        server: &str,
+        port: u16,
+        timeout: Option<Duration>,
+        tls_parameters: Option<&TlsParameters>,
+        local_addr: Option<IpAddr>,
+        proxy: Option<&Proxy>,
+        connection_attempt_delay: Option<Duration>,
+        socket_options: Option<&SocketOptions>,
+    ) -> Result<NetworkStream, Error> {
+        let tcp_stream = try_connect(
+            server,
+            port,
+            timeout,
+            local_addr,
+            proxy,
+            connection_attempt_delay,
+            socket_options,
+        )?;
         let mut stream = NetworkStream::new(InnerNetworkStream::Tcp(tcp_stream));
         if let Some(tls_parameters) = tls_parameters {
             stream.upgrade_tls(tls_parameters)?;
@@ -134,6 +176,55 @@ impl NetworkStream {
         Ok(stream)
     }
 
+    /// Connects to `server`, optionally upgrading to TLS, driving the whole operation against a
+    /// single wall-clock `deadline` rather than separate per-socket timeouts — mirroring the
+    /// `tcp-stream` crate's non-blocking `try_connect`/`into_tls` flow. The socket is put in
+    /// non-blocking mode; if the TCP connect or the TLS handshake can't finish yet because the
+    /// socket isn't ready, a resumable [`MidHandshake`] is returned via
+    /// [`HandshakeResult::WouldBlock`] so a caller integrating lettre into their own event loop
+    /// can pick it back up once the socket is ready again. Once `deadline` has passed, an
+    /// unfinished connect or handshake fails with a "connect timed out" error instead.
+    ///
+    /// Unlike [`NetworkStream::connect_with_options`], proxying and address racing aren't
+    /// supported here: only one resolved address is attempted.
+    pub fn connect_deadline(
+        server: &str,
+        port: u16,
+        deadline: Instant,
+        tls_parameters: Option<&TlsParameters>,
+        local_addr: Option<IpAddr>,
+        socket_options: Option<&SocketOptions>,
+    ) -> Result<HandshakeResult, Error> {
+        let addr = (server, port)
+            .to_socket_addrs()
+            .map_err(error::connection)?
+            .find(|resolved_addr| resolved_address_filter(resolved_addr, local_addr))
+            .ok_or_else(|| error::connection("could not resolve to any address"))?;
+
+        let socket =
+            socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+                .map_err(error::connection)?;
+        bind_local_address(&socket, &addr, local_addr)?;
+        if let Some(socket_options) = socket_options {
+            socket_options.apply(&socket)?;
+        }
+        socket.set_nonblocking(true).map_err(error::connection)?;
+
+        match poll_connect(&socket, addr)? {
+            true => finish_tcp_connect(socket.into(), tls_parameters, deadline),
+            false => {
+                if Instant::now() >= deadline {
+                    return Err(error::connection("connect timed out"));
+                }
+                Ok(HandshakeResult::WouldBlock(MidHandshake::TcpConnect(
+                    socket,
+                    addr,
+                    tls_parameters.cloned(),
+                )))
+            }
+        }
+    }
+
     pub fn upgrade_tls(&mut self, tls_parameters: &TlsParameters) -> Result<(), Error> {
         match &self.inner {
             #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
@@ -163,7 +254,7 @@ impl NetworkStream {
         tcp_stream: TcpStream,
         tls_parameters: &TlsParameters,
     ) -> Result<InnerNetworkStream, Error> {
-        Ok(match &tls_parameters.connector {
+        let inner = match &tls_parameters.connector {
             #[cfg(feature = "native-tls")]
             InnerTlsParameters::NativeTls(connector) => {
                 let stream = connector
@@ -180,7 +271,11 @@ impl NetworkStream {
                 let stream = StreamOwned::new(connection, tcp_stream);
                 InnerNetworkStream::RustlsTls(stream)
             }
-        })
+        };
+
+        verify_peer_certificate(&inner, tls_parameters.peer_verifier().as_ref())?;
+
+        Ok(inner)
     }
 
     pub fn is_encrypted(&self) -> bool {
@@ -190,6 +285,7 @@ impl NetworkStream {
             InnerNetworkStream::NativeTls(_) => true,
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(_) => true,
+            InnerNetworkStream::Boxed(ref s) => s.is_encrypted(),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 false
@@ -201,6 +297,9 @@ impl NetworkStream {
     pub fn peer_certificate(&self) -> Result<Vec<u8>, Error> {
         match &self.inner {
             InnerNetworkStream::Tcp(_) => Err(error::client("Connection is not encrypted")),
+            InnerNetworkStream::Boxed(_) => Err(error::client(
+                "peer certificate is not available for a user-provided stream",
+            )),
             #[cfg(feature = "native-tls")]
             InnerNetworkStream::NativeTls(stream) => Ok(stream
                 .peer_certificate()
@@ -232,6 +331,7 @@ impl NetworkStream {
             InnerNetworkStream::RustlsTls(ref mut stream) => {
                 stream.get_ref().set_read_timeout(duration)
             }
+            InnerNetworkStream::Boxed(ref mut stream) => stream.set_read_timeout(duration),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(())
@@ -252,6 +352,7 @@ impl NetworkStream {
             InnerNetworkStream::RustlsTls(ref mut stream) => {
                 stream.get_ref().set_write_timeout(duration)
             }
+            InnerNetworkStream::Boxed(ref mut stream) => stream.set_write_timeout(duration),
 
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
@@ -269,6 +370,7 @@ impl Read for NetworkStream {
             InnerNetworkStream::NativeTls(ref mut s) => s.read(buf),
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(ref mut s) => s.read(buf),
+            InnerNetworkStream::Boxed(ref mut s) => s.read(buf),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(0)
@@ -285,6 +387,7 @@ impl Write for NetworkStream {
             InnerNetworkStream::NativeTls(ref mut s) => s.write(buf),
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(ref mut s) => s.write(buf),
+            InnerNetworkStream::Boxed(ref mut s) => s.write(buf),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(0)
@@ -299,6 +402,7 @@ impl Write for NetworkStream {
             InnerNetworkStream::NativeTls(ref mut s) => s.flush(),
             #[cfg(feature = "rustls-tls")]
             InnerNetworkStream::RustlsTls(ref mut s) => s.flush(),
+            InnerNetworkStream::Boxed(ref mut s) => s.flush(),
             InnerNetworkStream::None => {
                 debug_assert!(false, "InnerNetworkStream::None must never be built");
                 Ok(())
@@ -307,6 +411,1008 @@ impl Write for NetworkStream {
     }
 }
 
+/// Default "Connection Attempt Delay" from RFC 8305 §8: how long to wait before starting the
+/// next staggered connection attempt while racing addresses.
+const DEFAULT_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Low-level socket options applied to the TCP socket before it's connected, for callers who
+/// need `TCP_NODELAY`, `SO_KEEPALIVE` or `SO_LINGER` tuned on the SMTP connection — e.g. to
+/// avoid Nagle-induced stalls on the small writes this protocol generates, or to have
+/// long-lived pooled connections detect dead peers via keepalive probes.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Keepalive>,
+    linger: Option<Option<Duration>>,
+}
+
+impl SocketOptions {
+    /// Creates an empty set of socket options; the OS defaults are used for anything left
+    /// unset.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `TCP_NODELAY`, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables `SO_KEEPALIVE` with the given parameters.
+    pub fn keepalive(mut self, keepalive: Keepalive) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Sets `SO_LINGER`. Pass `None` to disable lingering on close.
+    pub fn linger(mut self, duration: Option<Duration>) -> Self {
+        self.linger = Some(duration);
+        self
+    }
+
+    fn apply(&self, socket: &socket2::Socket) -> Result<(), Error> {
+        if let Some(nodelay) = self.nodelay {
+            socket.set_nodelay(nodelay).map_err(error::connection)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            socket
+                .set_tcp_keepalive(&keepalive.to_socket2())
+                .map_err(error::connection)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(linger).map_err(error::connection)?;
+        }
+        Ok(())
+    }
+}
+
+/// `SO_KEEPALIVE` parameters for [`SocketOptions::keepalive`].
+///
+/// Kept as lettre's own type, rather than re-exporting `socket2::TcpKeepalive` directly, so a
+/// `socket2` upgrade can't become a breaking change for lettre's public API.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    time: Duration,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+}
+
+impl Keepalive {
+    /// Starts a keepalive configuration that probes after `time` of inactivity.
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    /// Sets the interval between successive probes once the connection is idle.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// Sets the number of unacknowledged probes to send before considering the connection dead.
+    ///
+    /// Not supported on every platform; ignored where the OS doesn't expose this knob.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    fn to_socket2(self) -> TcpKeepalive {
+        let mut keepalive = TcpKeepalive::new().with_time(self.time);
+        if let Some(interval) = self.interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "linux",
+            target_os = "netbsd",
+            windows,
+        ))]
+        if let Some(retries) = self.retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+        keepalive
+    }
+}
+
+/// Runs the peer-certificate callback configured via `TlsParameters`'s pinning hook, if any,
+/// against the chain presented during the handshake that just produced `inner`. Used to enforce
+/// SPKI/certificate pinning for servers with a known, fixed certificate, as a defense against a
+/// compromised CA.
+#[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+fn verify_peer_certificate(
+    inner: &InnerNetworkStream,
+    verifier: Option<&PeerCertificateVerifier>,
+) -> Result<(), Error> {
+    let verifier = match verifier {
+        Some(verifier) => verifier,
+        None => return Ok(()),
+    };
+
+    let chain = peer_certificate_chain(inner)?;
+    if !verifier(&chain) {
+        return Err(error::tls("peer certificate rejected by custom verifier"));
+    }
+    Ok(())
+}
+
+/// Extracts the DER-encoded peer certificate chain, leaf first, from a just-upgraded TLS
+/// stream.
+///
+/// Backend-dependent: rustls's `peer_certificates()` returns the whole chain the server sent,
+/// but native-tls's `peer_certificate()` only ever exposes the leaf certificate, so under the
+/// `native-tls` feature this always returns a single-element vec. A `peer_verifier` callback
+/// that inspects anything past `chain[0]` will only ever see it with `rustls-tls`.
+#[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+fn peer_certificate_chain(inner: &InnerNetworkStream) -> Result<Vec<Vec<u8>>, Error> {
+    match inner {
+        #[cfg(feature = "native-tls")]
+        InnerNetworkStream::NativeTls(stream) => Ok(vec![stream
+            .peer_certificate()
+            .map_err(error::tls)?
+            .ok_or_else(|| error::client("server did not present a certificate"))?
+            .to_der()
+            .map_err(error::tls)?]),
+        #[cfg(feature = "rustls-tls")]
+        InnerNetworkStream::RustlsTls(stream) => Ok(stream
+            .conn
+            .peer_certificates()
+            .ok_or_else(|| error::client("server did not present a certificate"))?
+            .iter()
+            .map(|cert| cert.0.clone())
+            .collect()),
+        _ => unreachable!("upgrade_tls_impl only ever builds TLS variants"),
+    }
+}
+
+/// A TCP connect or TLS handshake started by [`NetworkStream::connect_deadline`] that couldn't
+/// complete yet because the socket wasn't ready, while the deadline hadn't passed. Resume it with [`MidHandshake::resume`] once the socket is ready again, e.g.
+/// after your event loop reports it as readable/writable; each resume only checks the socket
+/// once and returns immediately, so it never blocks the calling thread waiting for readiness.
+pub enum MidHandshake {
+    /// The TCP connect itself hasn't finished yet.
+    TcpConnect(socket2::Socket, SocketAddr, Option<TlsParameters>),
+    #[cfg(feature = "native-tls")]
+    NativeTls(
+        native_tls::MidHandshakeTlsStream<TcpStream>,
+        Option<PeerCertificateVerifier>,
+    ),
+    #[cfg(feature = "rustls-tls")]
+    RustlsTls(
+        StreamOwned<ClientConnection, TcpStream>,
+        Option<PeerCertificateVerifier>,
+    ),
+}
+
+impl MidHandshake {
+    /// Resumes a previously-yielded handshake against a new deadline.
+    pub fn resume(self, deadline: Instant) -> Result<HandshakeResult, Error> {
+        match self {
+            MidHandshake::TcpConnect(socket, addr, tls_parameters) => {
+                resume_tcp_connect(socket, addr, tls_parameters, deadline)
+            }
+            #[cfg(feature = "native-tls")]
+            MidHandshake::NativeTls(mid, verifier) => {
+                resume_native_mid_handshake(mid, deadline, verifier)
+            }
+            #[cfg(feature = "rustls-tls")]
+            MidHandshake::RustlsTls(stream, verifier) => {
+                resume_rustls_mid_handshake(stream, deadline, verifier)
+            }
+        }
+    }
+}
+
+/// The outcome of driving a [`NetworkStream::connect_deadline`] call up to its deadline.
+pub enum HandshakeResult {
+    /// The connection (and TLS upgrade, if requested) completed.
+    Stream(NetworkStream),
+    /// Still in progress; resume with [`MidHandshake::resume`] once the socket is ready again.
+    WouldBlock(MidHandshake),
+}
+
+fn is_would_block(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Raw OS error codes for the handful of `connect()` outcomes this module needs to recognize,
+/// defined locally rather than pulled in via `libc`/`windows-sys` — that would add a direct
+/// dependency on either crate just to name a few constants.
+#[cfg(unix)]
+mod errno {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(super) const EINPROGRESS: i32 = 115;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(super) const EALREADY: i32 = 114;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub(super) const EISCONN: i32 = 106;
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    pub(super) const EINPROGRESS: i32 = 36;
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    pub(super) const EALREADY: i32 = 37;
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    pub(super) const EISCONN: i32 = 56;
+}
+
+#[cfg(windows)]
+mod errno {
+    pub(super) const WSAEWOULDBLOCK: i32 = 10035;
+    pub(super) const WSAEISCONN: i32 = 10056;
+}
+
+/// True if `err` is a non-blocking `connect()` reporting "still in progress" rather than an
+/// actual failure. On Unix this is `EINPROGRESS` (first call) or `EALREADY` (subsequent polls);
+/// neither is mapped to [`io::ErrorKind::WouldBlock`] by `std`, unlike the `EAGAIN`/`EWOULDBLOCK`
+/// that real I/O (as opposed to `connect`) reports.
+fn is_connect_in_progress(err: &io::Error) -> bool {
+    if err.kind() == io::ErrorKind::WouldBlock {
+        return true;
+    }
+    match err.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == errno::EINPROGRESS || code == errno::EALREADY,
+        #[cfg(windows)]
+        Some(code) => code == errno::WSAEWOULDBLOCK,
+        #[cfg(not(any(unix, windows)))]
+        Some(_) => false,
+        None => false,
+    }
+}
+
+/// True if `err` is a non-blocking `connect()` reporting that the socket is already connected
+/// (Unix `EISCONN`, Windows `WSAEISCONN`) — re-issuing `connect()` on an established non-blocking
+/// socket reports this as an `Err`, not as the `Ok(())` a first-time caller would expect.
+fn is_already_connected(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        #[cfg(unix)]
+        Some(code) => code == errno::EISCONN,
+        #[cfg(windows)]
+        Some(code) => code == errno::WSAEISCONN,
+        #[cfg(not(any(unix, windows)))]
+        Some(_) => false,
+        None => false,
+    }
+}
+
+/// Makes a single non-blocking attempt to complete `connect()`, returning `true` once it has
+/// succeeded and `false` if it's still in progress. Never waits for the socket to become
+/// ready — callers drive retries themselves (directly, or via [`MidHandshake::resume`]), so the
+/// calling thread is never blocked waiting on the network.
+fn poll_connect(socket: &socket2::Socket, addr: SocketAddr) -> Result<bool, Error> {
+    match socket.connect(&addr.into()) {
+        Ok(()) => Ok(true),
+        Err(err) if is_already_connected(&err) => Ok(true),
+        Err(err) if is_connect_in_progress(&err) => Ok(false),
+        Err(err) => Err(error::connection(err)),
+    }
+}
+
+/// Resumes a [`MidHandshake::TcpConnect`]: makes one more non-blocking attempt to complete the
+/// connect, moving on to the TLS handshake (or returning the plain stream) once it succeeds.
+fn resume_tcp_connect(
+    socket: socket2::Socket,
+    addr: SocketAddr,
+    tls_parameters: Option<TlsParameters>,
+    deadline: Instant,
+) -> Result<HandshakeResult, Error> {
+    match poll_connect(&socket, addr)? {
+        true => finish_tcp_connect(socket.into(), tls_parameters.as_ref(), deadline),
+        false => {
+            if Instant::now() >= deadline {
+                return Err(error::connection("connect timed out"));
+            }
+            Ok(HandshakeResult::WouldBlock(MidHandshake::TcpConnect(
+                socket,
+                addr,
+                tls_parameters,
+            )))
+        }
+    }
+}
+
+/// Dispatches a connected, still-non-blocking TCP socket to the TLS handshake, or hands it back
+/// as a plain stream, once [`NetworkStream::connect_deadline`]'s TCP connect has finished.
+fn finish_tcp_connect(
+    tcp_stream: TcpStream,
+    tls_parameters: Option<&TlsParameters>,
+    deadline: Instant,
+) -> Result<HandshakeResult, Error> {
+    match tls_parameters {
+        #[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+        Some(tls_parameters) => upgrade_tls_nonblocking(tcp_stream, tls_parameters, deadline),
+        #[cfg(not(any(feature = "native-tls", feature = "rustls-tls")))]
+        Some(_) => panic!("Trying to upgrade an NetworkStream without having enabled either the native-tls or the rustls-tls feature"),
+        None => {
+            // No TLS upgrade follows, so the handshake is done: restore blocking mode before
+            // handing the stream back, matching every other NetworkStream constructor.
+            tcp_stream.set_nonblocking(false).map_err(error::connection)?;
+            Ok(HandshakeResult::Stream(NetworkStream::new(
+                InnerNetworkStream::Tcp(tcp_stream),
+            )))
+        }
+    }
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls-tls"))]
+fn upgrade_tls_nonblocking(
+    tcp_stream: TcpStream,
+    tls_parameters: &TlsParameters,
+    deadline: Instant,
+) -> Result<HandshakeResult, Error> {
+    let verifier = tls_parameters.peer_verifier();
+    match &tls_parameters.connector {
+        #[cfg(feature = "native-tls")]
+        InnerTlsParameters::NativeTls(connector) => {
+            match connector.connect(tls_parameters.domain(), tcp_stream) {
+                Ok(stream) => finish_native_handshake(stream, verifier),
+                Err(native_tls::HandshakeError::WouldBlock(mid)) => {
+                    resume_native_mid_handshake(mid, deadline, verifier)
+                }
+                Err(native_tls::HandshakeError::Failure(err)) => Err(error::connection(err)),
+            }
+        }
+        #[cfg(feature = "rustls-tls")]
+        InnerTlsParameters::RustlsTls(connector) => {
+            let domain = ServerName::try_from(tls_parameters.domain())
+                .map_err(|_| error::connection("domain isn't a valid DNS name"))?;
+            let connection =
+                ClientConnection::new(connector.clone(), domain).map_err(error::connection)?;
+            let stream = StreamOwned::new(connection, tcp_stream);
+            resume_rustls_mid_handshake(stream, deadline, verifier)
+        }
+    }
+}
+
+/// Verifies the peer certificate (if pinning is configured) and wraps a completed native-tls
+/// handshake into a [`HandshakeResult`].
+#[cfg(feature = "native-tls")]
+fn finish_native_handshake(
+    stream: TlsStream<TcpStream>,
+    verifier: Option<PeerCertificateVerifier>,
+) -> Result<HandshakeResult, Error> {
+    // The handshake is done: restore blocking mode before handing the stream back, matching
+    // every other NetworkStream constructor.
+    stream.get_ref().set_nonblocking(false).map_err(error::connection)?;
+    let inner = InnerNetworkStream::NativeTls(stream);
+    verify_peer_certificate(&inner, verifier.as_ref())?;
+    Ok(HandshakeResult::Stream(NetworkStream::new(inner)))
+}
+
+/// Verifies the peer certificate (if pinning is configured) and wraps a completed rustls
+/// handshake into a [`HandshakeResult`].
+#[cfg(feature = "rustls-tls")]
+fn finish_rustls_handshake(
+    stream: StreamOwned<ClientConnection, TcpStream>,
+    verifier: Option<PeerCertificateVerifier>,
+) -> Result<HandshakeResult, Error> {
+    // The handshake is done: restore blocking mode before handing the stream back, matching
+    // every other NetworkStream constructor.
+    stream.sock.set_nonblocking(false).map_err(error::connection)?;
+    let inner = InnerNetworkStream::RustlsTls(stream);
+    verify_peer_certificate(&inner, verifier.as_ref())?;
+    Ok(HandshakeResult::Stream(NetworkStream::new(inner)))
+}
+
+/// Makes one more attempt at a native-tls handshake that previously reported `WouldBlock`,
+/// without waiting for the socket to become ready — callers drive retries themselves (directly,
+/// or via [`MidHandshake::resume`]).
+#[cfg(feature = "native-tls")]
+fn resume_native_mid_handshake(
+    mut mid: native_tls::MidHandshakeTlsStream<TcpStream>,
+    deadline: Instant,
+    verifier: Option<PeerCertificateVerifier>,
+) -> Result<HandshakeResult, Error> {
+    match mid.handshake() {
+        Ok(stream) => finish_native_handshake(stream, verifier),
+        Err(native_tls::HandshakeError::WouldBlock(next)) => {
+            if Instant::now() >= deadline {
+                return Err(error::connection("connect timed out"));
+            }
+            Ok(HandshakeResult::WouldBlock(MidHandshake::NativeTls(
+                next, verifier,
+            )))
+        }
+        Err(native_tls::HandshakeError::Failure(err)) => Err(error::connection(err)),
+    }
+}
+
+/// Makes one more attempt at a rustls handshake that previously reported `WouldBlock`, without
+/// waiting for the socket to become ready — callers drive retries themselves (directly, or via
+/// [`MidHandshake::resume`]).
+#[cfg(feature = "rustls-tls")]
+fn resume_rustls_mid_handshake(
+    mut stream: StreamOwned<ClientConnection, TcpStream>,
+    deadline: Instant,
+    verifier: Option<PeerCertificateVerifier>,
+) -> Result<HandshakeResult, Error> {
+    if !stream.conn.is_handshaking() {
+        return finish_rustls_handshake(stream, verifier);
+    }
+    match stream.conn.complete_io(&mut stream.sock) {
+        Ok(_) if !stream.conn.is_handshaking() => finish_rustls_handshake(stream, verifier),
+        Ok(_) => {
+            if Instant::now() >= deadline {
+                Err(error::connection("connect timed out"))
+            } else {
+                Ok(HandshakeResult::WouldBlock(MidHandshake::RustlsTls(
+                    stream, verifier,
+                )))
+            }
+        }
+        Err(err) if is_would_block(&err) => {
+            if Instant::now() >= deadline {
+                Err(error::connection("connect timed out"))
+            } else {
+                Ok(HandshakeResult::WouldBlock(MidHandshake::RustlsTls(
+                    stream, verifier,
+                )))
+            }
+        }
+        Err(err) => Err(error::connection(err)),
+    }
+}
+
+fn try_connect(
+    server: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+    proxy: Option<&Proxy>,
+    connection_attempt_delay: Option<Duration>,
+    socket_options: Option<&SocketOptions>,
+) -> Result<TcpStream, Error> {
+    match proxy {
+        Some(proxy) => {
+            connect_via_proxy(proxy, server, port, timeout, local_addr, socket_options)
+        }
+        None => connect_direct(
+            server,
+            port,
+            timeout,
+            local_addr,
+            connection_attempt_delay,
+            socket_options,
+        ),
+    }
+}
+
+fn connect_direct(
+    server: &str,
+    port: u16,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+    connection_attempt_delay: Option<Duration>,
+    socket_options: Option<&SocketOptions>,
+) -> Result<TcpStream, Error> {
+    let mut addrs: Vec<SocketAddr> = (server, port)
+        .to_socket_addrs()
+        .map_err(error::connection)?
+        .filter(|resolved_addr| resolved_address_filter(resolved_addr, local_addr))
+        .collect();
+
+    if addrs.is_empty() {
+        return Err(error::connection("could not resolve to any address"));
+    }
+
+    interleave_by_family(&mut addrs);
+
+    if addrs.len() == 1 {
+        return connect_one(addrs[0], timeout, local_addr, socket_options);
+    }
+
+    race_connect(
+        addrs,
+        timeout,
+        local_addr,
+        connection_attempt_delay.unwrap_or(DEFAULT_CONNECTION_ATTEMPT_DELAY),
+        socket_options.cloned().map(Arc::new),
+    )
+}
+
+/// Reorders `addrs` so IPv6 and IPv4 addresses alternate, IPv6 first, per RFC 8305's "Happy
+/// Eyeballs" address interleaving.
+fn interleave_by_family(addrs: &mut Vec<SocketAddr>) {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.drain(..).partition(|addr| addr.is_ipv6());
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    loop {
+        match (v6.next(), v4.next()) {
+            (Some(a), Some(b)) => {
+                addrs.push(a);
+                addrs.push(b);
+            }
+            (Some(a), None) => addrs.push(a),
+            (None, Some(b)) => addrs.push(b),
+            (None, None) => break,
+        }
+    }
+}
+
+/// Resolves `server` and connects to the first address that works, trying each in turn. This is
+/// the simple, sequential behavior [`NetworkStream::connect`] has always had, kept separate from
+/// [`connect_direct`]'s RFC 8305 racing so that entry point's behavior doesn't change.
+fn connect_first_reachable<T: ToSocketAddrs>(
+    server: T,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+) -> Result<TcpStream, Error> {
+    let addrs = server
+        .to_socket_addrs()
+        .map_err(error::connection)?
+        .filter(|resolved_addr| resolved_address_filter(resolved_addr, local_addr));
+
+    let mut last_err = None;
+    for addr in addrs {
+        match connect_one(addr, timeout, local_addr, None) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| error::connection("could not resolve to any address")))
+}
+
+fn connect_one(
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+    socket_options: Option<&SocketOptions>,
+) -> Result<TcpStream, Error> {
+    let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))
+        .map_err(error::connection)?;
+    bind_local_address(&socket, &addr, local_addr)?;
+    if let Some(socket_options) = socket_options {
+        socket_options.apply(&socket)?;
+    }
+
+    match timeout {
+        Some(timeout) => socket
+            .connect_timeout(&addr.into(), timeout)
+            .map_err(error::connection)?,
+        None => socket.connect(&addr.into()).map_err(error::connection)?,
+    }
+
+    Ok(socket.into())
+}
+
+/// Races connection attempts across `addrs`, starting one every `attempt_delay` on its own
+/// detached thread, and returns the socket for the first attempt to succeed as soon as it's
+/// available — it does **not** wait for the other attempts to finish first. Each connect is a
+/// blocking call that can't be interrupted mid-syscall, so a still-running attempt can't be
+/// killed outright; instead, the losers are signalled to abort via `aborted` and, once their
+/// blocking connect eventually returns, they drop their socket without reporting it anywhere.
+fn race_connect(
+    addrs: Vec<SocketAddr>,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+    attempt_delay: Duration,
+    socket_options: Option<Arc<SocketOptions>>,
+) -> Result<TcpStream, Error> {
+    let (tx, rx) = mpsc::channel();
+    let aborted: Vec<Arc<AtomicBool>> = addrs.iter().map(|_| Arc::new(AtomicBool::new(false))).collect();
+
+    for (i, addr) in addrs.into_iter().enumerate() {
+        let tx = tx.clone();
+        let aborted = aborted[i].clone();
+        let socket_options = socket_options.clone();
+        let delay = attempt_delay.saturating_mul(i as u32);
+        thread::spawn(move || {
+            if !delay.is_zero() {
+                thread::sleep(delay);
+            }
+            if aborted.load(Ordering::SeqCst) {
+                return;
+            }
+            let result = connect_one(addr, timeout, local_addr, socket_options.as_deref());
+            if !aborted.load(Ordering::SeqCst) {
+                // If the receiver has already gone away, our socket is simply dropped here.
+                let _ = tx.send(result);
+            }
+        });
+    }
+    drop(tx);
+
+    let mut last_err = None;
+    for result in rx {
+        match result {
+            Ok(stream) => {
+                // Signal every attempt still running to drop its socket once it unblocks; we
+                // don't wait around for that to actually happen.
+                for aborted in &aborted {
+                    aborted.store(true, Ordering::SeqCst);
+                }
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| error::connection("could not connect to any address")))
+}
+
+/// The proxy scheme to use when tunnelling the SMTP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyScheme {
+    /// SOCKS5, resolving the target host locally before sending the proxy an address.
+    Socks5,
+    /// SOCKS5, letting the proxy itself resolve the target host.
+    Socks5h,
+    /// HTTP proxy, tunnelled with the `CONNECT` method.
+    Http,
+}
+
+/// Describes a SOCKS5 or HTTP-CONNECT proxy to tunnel the SMTP connection through.
+///
+/// Build one with [`Proxy::socks5`], [`Proxy::socks5h`] or [`Proxy::http`], optionally attaching
+/// [`Proxy::credentials`], and pass it to [`NetworkStream::connect_with_options`]. The TCP
+/// connection is tunnelled to the real SMTP server through the proxy before any TLS upgrade
+/// happens, so encryption stays end-to-end with the server.
+///
+/// An IPv6 literal proxy address must be bracketed, e.g. `[::1]:1080`.
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    scheme: ProxyScheme,
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Tunnel through a SOCKS5 proxy listening at `addr` (`host:port`), resolving the target
+    /// host locally before handing the proxy an IP address.
+    pub fn socks5(addr: impl Into<String>) -> Self {
+        Self {
+            scheme: ProxyScheme::Socks5,
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Tunnel through a SOCKS5 proxy listening at `addr` (`host:port`), letting the proxy
+    /// resolve the target host itself.
+    pub fn socks5h(addr: impl Into<String>) -> Self {
+        Self {
+            scheme: ProxyScheme::Socks5h,
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Tunnel through an HTTP proxy listening at `addr` (`host:port`) using the `CONNECT`
+    /// method.
+    pub fn http(addr: impl Into<String>) -> Self {
+        Self {
+            scheme: ProxyScheme::Http,
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Attach username/password credentials, sent during the SOCKS5 handshake or as a
+    /// `Proxy-Authorization: Basic` header for HTTP proxies.
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+}
+
+/// Splits a `host:port` proxy address, accepting a bracketed IPv6 literal (`[::1]:1080`) as well
+/// as a plain hostname or IPv4 address. An unbracketed host containing a `:` is rejected rather
+/// than guessed at, since `rsplit_once(':')` would otherwise silently pick the wrong split point.
+fn split_proxy_addr(addr: &str) -> Result<(&str, u16), Error> {
+    if let Some(rest) = addr.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| error::connection("proxy address is missing closing ']' for an IPv6 literal"))?;
+        let port = rest
+            .strip_prefix(':')
+            .ok_or_else(|| error::connection("proxy address must be in the form [host]:port"))?;
+        let port: u16 = port
+            .parse()
+            .map_err(|_| error::connection("invalid proxy port"))?;
+        return Ok((host, port));
+    }
+
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| error::connection("proxy address must be in the form host:port"))?;
+    if host.contains(':') {
+        return Err(error::connection(
+            "proxy address looks like an IPv6 literal; wrap it in brackets, e.g. [::1]:1080",
+        ));
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| error::connection("invalid proxy port"))?;
+    Ok((host, port))
+}
+
+fn connect_via_proxy(
+    proxy: &Proxy,
+    target_host: &str,
+    target_port: u16,
+    timeout: Option<Duration>,
+    local_addr: Option<IpAddr>,
+    socket_options: Option<&SocketOptions>,
+) -> Result<TcpStream, Error> {
+    let (proxy_host, proxy_port) = split_proxy_addr(&proxy.addr)?;
+
+    let mut stream = connect_direct(
+        proxy_host,
+        proxy_port,
+        timeout,
+        local_addr,
+        None,
+        socket_options,
+    )?;
+
+    match proxy.scheme {
+        ProxyScheme::Socks5 | ProxyScheme::Socks5h => {
+            socks5_handshake(&mut stream, proxy, target_host, target_port)?
+        }
+        ProxyScheme::Http => http_connect_handshake(&mut stream, proxy, target_host, target_port)?,
+    }
+
+    Ok(stream)
+}
+
+/// Maximum length of a SOCKS5 username, password or domain name: the protocol prefixes each
+/// with a single length byte, so anything longer can't be represented at all.
+const SOCKS5_MAX_FIELD_LEN: usize = 0xff;
+
+/// Builds the SOCKS5 method-negotiation greeting. When credentials are available we still offer
+/// no-auth (`0x00`) alongside username/password (`0x02`), so the handshake can fall back to
+/// no-auth if that's all the proxy supports, rather than forcing username/password whenever
+/// credentials happen to be configured.
+fn socks5_greeting(has_credentials: bool) -> Vec<u8> {
+    if has_credentials {
+        vec![0x05, 0x02, 0x00, 0x02]
+    } else {
+        vec![0x05, 0x01, 0x00]
+    }
+}
+
+/// Builds a SOCKS5 username/password sub-negotiation request (RFC 1929). Rejects credentials
+/// too long to fit the protocol's single-byte length prefix instead of silently truncating them.
+fn socks5_auth_request(username: &str, password: &str) -> Result<Vec<u8>, Error> {
+    if username.len() > SOCKS5_MAX_FIELD_LEN || password.len() > SOCKS5_MAX_FIELD_LEN {
+        return Err(error::connection(
+            "SOCKS5 username and password must each be at most 255 bytes",
+        ));
+    }
+
+    let mut auth = vec![0x01, username.len() as u8];
+    auth.extend_from_slice(username.as_bytes());
+    auth.push(password.len() as u8);
+    auth.extend_from_slice(password.as_bytes());
+    Ok(auth)
+}
+
+/// Builds a SOCKS5 CONNECT request (RFC 1928 §4) for `target_host`/`target_port`. For
+/// [`ProxyScheme::Socks5h`] the hostname is sent as-is for the proxy to resolve; otherwise
+/// `resolved_ip` (already looked up by the caller) is sent as an IPv4/IPv6 address.
+fn socks5_connect_request(
+    scheme: ProxyScheme,
+    target_host: &str,
+    resolved_ip: Option<IpAddr>,
+    target_port: u16,
+) -> Result<Vec<u8>, Error> {
+    let mut request = vec![0x05, 0x01, 0x00];
+    if scheme == ProxyScheme::Socks5h {
+        if target_host.len() > SOCKS5_MAX_FIELD_LEN {
+            return Err(error::connection(
+                "SOCKS5 target hostname must be at most 255 bytes",
+            ));
+        }
+        request.push(0x03);
+        request.push(target_host.len() as u8);
+        request.extend_from_slice(target_host.as_bytes());
+    } else {
+        let ip = resolved_ip.expect("resolved_ip is required unless scheme is Socks5h");
+        match ip {
+            IpAddr::V4(ip) => {
+                request.push(0x01);
+                request.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                request.push(0x04);
+                request.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    request.extend_from_slice(&target_port.to_be_bytes());
+    Ok(request)
+}
+
+fn socks5_handshake(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<(), Error> {
+    let greeting = socks5_greeting(proxy.credentials.is_some());
+    stream.write_all(&greeting).map_err(error::connection)?;
+
+    let mut selected = [0u8; 2];
+    stream.read_exact(&mut selected).map_err(error::connection)?;
+    if selected[0] != 0x05 {
+        return Err(error::connection("unexpected SOCKS5 version in server reply"));
+    }
+
+    match selected[1] {
+        0x00 => {}
+        0x02 => {
+            let (username, password) = proxy
+                .credentials
+                .as_ref()
+                .ok_or_else(|| error::connection("SOCKS5 proxy requires credentials"))?;
+            let auth = socks5_auth_request(username, password)?;
+            stream.write_all(&auth).map_err(error::connection)?;
+
+            let mut auth_reply = [0u8; 2];
+            stream
+                .read_exact(&mut auth_reply)
+                .map_err(error::connection)?;
+            if auth_reply[1] != 0x00 {
+                return Err(error::connection("SOCKS5 proxy authentication failed"));
+            }
+        }
+        0xff => {
+            return Err(error::connection(
+                "SOCKS5 proxy rejected all authentication methods",
+            ))
+        }
+        method => {
+            return Err(error::connection(format!(
+                "SOCKS5 proxy selected unsupported authentication method {method:#x}"
+            )))
+        }
+    }
+
+    let resolved_ip = if proxy.scheme == ProxyScheme::Socks5h {
+        None
+    } else {
+        Some(
+            target_host
+                .parse()
+                .or_else(|_| resolve_one(target_host))
+                .map_err(error::connection)?,
+        )
+    };
+    let request = socks5_connect_request(proxy.scheme, target_host, resolved_ip, target_port)?;
+    stream.write_all(&request).map_err(error::connection)?;
+
+    let mut reply_head = [0u8; 4];
+    stream
+        .read_exact(&mut reply_head)
+        .map_err(error::connection)?;
+    if reply_head[1] != 0x00 {
+        return Err(error::connection(format!(
+            "SOCKS5 proxy refused the connection, reply code {:#x}",
+            reply_head[1]
+        )));
+    }
+
+    // Consume the bound address the proxy reports back; its contents aren't needed.
+    let remaining = match reply_head[3] {
+        0x01 => 4 + 2,
+        0x04 => 16 + 2,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).map_err(error::connection)?;
+            len[0] as usize + 2
+        }
+        atyp => {
+            return Err(error::connection(format!(
+                "unsupported SOCKS5 address type {atyp:#x}"
+            )))
+        }
+    };
+    let mut rest = vec![0u8; remaining];
+    stream.read_exact(&mut rest).map_err(error::connection)?;
+
+    Ok(())
+}
+
+/// Resolves `host` to a single IP address, for SOCKS5 requests that must carry an address
+/// rather than a hostname.
+fn resolve_one(host: &str) -> io::Result<IpAddr> {
+    (host, 0)
+        .to_socket_addrs()?
+        .next()
+        .map(|addr| addr.ip())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "could not resolve host"))
+}
+
+/// Upper bound on the header bytes we'll read from an HTTP `CONNECT` response before giving up -
+/// a hostile or broken proxy could otherwise stream data forever, one byte at a time.
+const MAX_HTTP_CONNECT_RESPONSE_HEADER_BYTES: usize = 8 * 1024;
+
+/// True if `status_line` (the first line of an HTTP response, e.g. `"HTTP/1.1 200 Connection
+/// established"`) reports success. Checks the status-code token itself rather than substring
+/// matching, so it isn't fooled by a missing reason phrase (`"HTTP/1.1 200"`, no trailing space)
+/// or by "200" appearing elsewhere in the line.
+fn http_connect_succeeded(status_line: &str) -> bool {
+    status_line
+        .split_ascii_whitespace()
+        .nth(1)
+        .map(|code| code == "200")
+        .unwrap_or(false)
+}
+
+fn http_connect_handshake(
+    stream: &mut TcpStream,
+    proxy: &Proxy,
+    target_host: &str,
+    target_port: u16,
+) -> Result<(), Error> {
+    let mut request =
+        format!("CONNECT {target_host}:{target_port} HTTP/1.1\r\nHost: {target_host}:{target_port}\r\n");
+    if let Some((username, password)) = &proxy.credentials {
+        let credentials = base64_engine.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .map_err(error::connection)?;
+
+    let mut response = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte).map_err(error::connection)?;
+        response.push(byte[0]);
+        if response.ends_with(b"\r\n\r\n") {
+            break;
+        }
+        if response.len() > MAX_HTTP_CONNECT_RESPONSE_HEADER_BYTES {
+            return Err(error::connection("HTTP proxy CONNECT response too large"));
+        }
+    }
+    let status_line = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    if !http_connect_succeeded(&status_line) {
+        return Err(error::connection(format!(
+            "HTTP proxy CONNECT failed: {status_line}"
+        )));
+    }
+
+    Ok(())
+}
+
 /// If the local address is set, binds the socket to this address.
 /// If local address is not set, then destination address is required to determine to the default
 /// local address on some platforms.
@@ -350,3 +1456,134 @@ pub(crate) fn resolved_address_filter(
         None => true,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(octet: u8) -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, octet)), 25)
+    }
+
+    fn v6(segment: u16) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, segment)), 25)
+    }
+
+    #[test]
+    fn interleave_by_family_alternates_starting_with_v6() {
+        let mut addrs = vec![v4(1), v4(2), v6(1), v6(2)];
+        interleave_by_family(&mut addrs);
+        assert_eq!(addrs, vec![v6(1), v4(1), v6(2), v4(2)]);
+    }
+
+    #[test]
+    fn interleave_by_family_appends_leftovers_of_the_longer_family() {
+        let mut addrs = vec![v4(1), v6(1), v6(2), v6(3)];
+        interleave_by_family(&mut addrs);
+        assert_eq!(addrs, vec![v6(1), v4(1), v6(2), v6(3)]);
+    }
+
+    #[test]
+    fn interleave_by_family_handles_a_single_family() {
+        let mut addrs = vec![v4(1), v4(2)];
+        interleave_by_family(&mut addrs);
+        assert_eq!(addrs, vec![v4(1), v4(2)]);
+    }
+
+    #[test]
+    fn socks5_greeting_offers_no_auth_and_user_pass_when_credentials_are_set() {
+        assert_eq!(socks5_greeting(true), vec![0x05, 0x02, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn socks5_greeting_offers_only_no_auth_without_credentials() {
+        assert_eq!(socks5_greeting(false), vec![0x05, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn socks5_auth_request_frames_username_and_password() {
+        let auth = socks5_auth_request("alice", "hunter2").unwrap();
+        assert_eq!(
+            auth,
+            vec![0x01, 5, b'a', b'l', b'i', b'c', b'e', 7, b'h', b'u', b'n', b't', b'e', b'r', b'2']
+        );
+    }
+
+    #[test]
+    fn socks5_auth_request_rejects_oversized_fields() {
+        let long = "a".repeat(256);
+        assert!(socks5_auth_request(&long, "short").is_err());
+        assert!(socks5_auth_request("short", &long).is_err());
+    }
+
+    #[test]
+    fn socks5_connect_request_sends_hostname_for_socks5h() {
+        let request =
+            socks5_connect_request(ProxyScheme::Socks5h, "example.com", None, 25).unwrap();
+        assert_eq!(
+            request,
+            vec![0x05, 0x01, 0x00, 0x03, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm', 0x00, 0x19]
+        );
+    }
+
+    #[test]
+    fn socks5_connect_request_sends_ipv4_for_socks5() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let request = socks5_connect_request(ProxyScheme::Socks5, "ignored", Some(ip), 25).unwrap();
+        assert_eq!(
+            request,
+            vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x19]
+        );
+    }
+
+    #[test]
+    fn socks5_connect_request_rejects_an_oversized_hostname() {
+        let long = "a".repeat(256);
+        assert!(socks5_connect_request(ProxyScheme::Socks5h, &long, None, 25).is_err());
+    }
+
+    #[test]
+    fn http_connect_succeeded_accepts_status_with_reason_phrase() {
+        assert!(http_connect_succeeded("HTTP/1.1 200 Connection established"));
+    }
+
+    #[test]
+    fn http_connect_succeeded_accepts_status_without_reason_phrase() {
+        assert!(http_connect_succeeded("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn http_connect_succeeded_rejects_non_200_status() {
+        assert!(!http_connect_succeeded("HTTP/1.1 407 Proxy Authentication Required"));
+    }
+
+    #[test]
+    fn http_connect_succeeded_does_not_match_200_elsewhere_in_the_line() {
+        assert!(!http_connect_succeeded("HTTP/1.1 404 Not Found (was 200 before)"));
+    }
+
+    #[test]
+    fn split_proxy_addr_accepts_hostname() {
+        assert_eq!(split_proxy_addr("proxy.example.com:1080").unwrap(), ("proxy.example.com", 1080));
+    }
+
+    #[test]
+    fn split_proxy_addr_accepts_ipv4() {
+        assert_eq!(split_proxy_addr("127.0.0.1:1080").unwrap(), ("127.0.0.1", 1080));
+    }
+
+    #[test]
+    fn split_proxy_addr_accepts_bracketed_ipv6() {
+        assert_eq!(split_proxy_addr("[::1]:1080").unwrap(), ("::1", 1080));
+    }
+
+    #[test]
+    fn split_proxy_addr_rejects_unbracketed_ipv6() {
+        assert!(split_proxy_addr("::1:1080").is_err());
+    }
+
+    #[test]
+    fn split_proxy_addr_rejects_missing_port() {
+        assert!(split_proxy_addr("proxy.example.com").is_err());
+    }
+}